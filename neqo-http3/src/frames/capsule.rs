@@ -4,35 +4,287 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-#[cfg(test)]
-use neqo_common::Encoder;
-use neqo_common::{Bytes, Decoder};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use neqo_common::{Bytes, Decoder, Encoder};
 
 use crate::Res;
 
-pub const CAPSULE_TYPE_DATAGRAM: u64 = 0x00;
+/// A Capsule Protocol type, as defined by
+/// <https://www.rfc-editor.org/rfc/rfc9297>. Mirrors [`super::hframe::HFrameType`]:
+/// a thin newtype over the wire value rather than a closed set of variants,
+/// since the type space is extended by other specifications (MASQUE,
+/// proxy-control capsules, ...) that this crate does not need to know about
+/// up front.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CapsuleType(pub u64);
+
+pub const CAPSULE_TYPE_DATAGRAM: CapsuleType = CapsuleType(0x00);
+/// `CONNECT-IP` address assignment, see
+/// <https://www.rfc-editor.org/rfc/rfc9484>.
+pub const CAPSULE_TYPE_ADDRESS_ASSIGN: CapsuleType = CapsuleType(0x01);
+/// `CONNECT-IP` address request, see
+/// <https://www.rfc-editor.org/rfc/rfc9484>.
+pub const CAPSULE_TYPE_ADDRESS_REQUEST: CapsuleType = CapsuleType(0x02);
+/// `CONNECT-IP` route advertisement, see
+/// <https://www.rfc-editor.org/rfc/rfc9484>.
+pub const CAPSULE_TYPE_ROUTE_ADVERTISEMENT: CapsuleType = CapsuleType(0x03);
+
+/// An IP address together with the request ID it was assigned to or
+/// requested for, as carried by `ADDRESS_ASSIGN`/`ADDRESS_REQUEST` capsules.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct IpAssignment {
+    pub request_id: u64,
+    pub ip_addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl IpAssignment {
+    fn decode(decoder: &mut Decoder) -> Res<Self> {
+        let request_id = decoder.decode_varint().ok_or(crate::Error::HttpFrame)?;
+        let ip_addr = decode_ip_addr(decoder)?;
+        let prefix_len = decoder.decode_byte().ok_or(crate::Error::HttpFrame)?;
+        Ok(Self {
+            request_id,
+            ip_addr,
+            prefix_len,
+        })
+    }
+
+    #[cfg(test)]
+    fn encode(&self, enc: &mut Encoder) {
+        enc.encode_varint(self.request_id);
+        encode_ip_addr(enc, self.ip_addr);
+        enc.encode_byte(self.prefix_len);
+    }
+}
+
+/// A range of IP addresses reachable through the tunnel, as carried by a
+/// `ROUTE_ADVERTISEMENT` capsule.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct IpAddressRange {
+    pub start: IpAddr,
+    pub end: IpAddr,
+    pub ip_proto: u8,
+}
+
+impl IpAddressRange {
+    /// Per RFC 9484 section 4.7, an `IP Address Range` carries a single `IP
+    /// Version` byte shared by both `Start IP Address` and `End IP
+    /// Address`, unlike `ADDRESS_ASSIGN`/`ADDRESS_REQUEST` where each
+    /// address has its own version byte.
+    fn decode(decoder: &mut Decoder) -> Res<Self> {
+        let ip_version = decoder.decode_byte().ok_or(crate::Error::HttpFrame)?;
+        let start = decode_ip_addr_with_version(decoder, ip_version)?;
+        let end = decode_ip_addr_with_version(decoder, ip_version)?;
+        let ip_proto = decoder.decode_byte().ok_or(crate::Error::HttpFrame)?;
+        Ok(Self {
+            start,
+            end,
+            ip_proto,
+        })
+    }
+
+    #[cfg(test)]
+    fn encode(&self, enc: &mut Encoder) {
+        enc.encode_byte(ip_version(self.start));
+        encode_ip_addr_octets(enc, self.start);
+        encode_ip_addr_octets(enc, self.end);
+        enc.encode_byte(self.ip_proto);
+    }
+}
+
+fn decode_ip_addr_with_version(decoder: &mut Decoder, ip_version: u8) -> Res<IpAddr> {
+    let len = match ip_version {
+        4 => 4,
+        6 => 16,
+        _ => return Err(crate::Error::HttpFrame),
+    };
+    let bytes = decoder.decode(len).ok_or(crate::Error::HttpFrame)?;
+    Ok(if ip_version == 4 {
+        IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))
+    } else {
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(bytes);
+        IpAddr::V6(Ipv6Addr::from(octets))
+    })
+}
+
+fn decode_ip_addr(decoder: &mut Decoder) -> Res<IpAddr> {
+    let ip_version = decoder.decode_byte().ok_or(crate::Error::HttpFrame)?;
+    decode_ip_addr_with_version(decoder, ip_version)
+}
+
+#[cfg(test)]
+fn ip_version(addr: IpAddr) -> u8 {
+    if addr.is_ipv4() {
+        4
+    } else {
+        6
+    }
+}
+
+#[cfg(test)]
+fn encode_ip_addr_octets(enc: &mut Encoder, addr: IpAddr) {
+    match addr {
+        IpAddr::V4(v4) => enc.encode(&v4.octets()),
+        IpAddr::V6(v6) => enc.encode(&v6.octets()),
+    }
+}
+
+#[cfg(test)]
+fn encode_ip_addr(enc: &mut Encoder, addr: IpAddr) {
+    enc.encode_byte(ip_version(addr));
+    encode_ip_addr_octets(enc, addr);
+}
+
+/// Body of an `ADDRESS_ASSIGN` capsule: zero or more assigned addresses.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct AddressAssignCapsule {
+    pub assigned: Vec<IpAssignment>,
+}
+
+/// Body of an `ADDRESS_REQUEST` capsule: zero or more requested addresses.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct AddressRequestCapsule {
+    pub requested: Vec<IpAssignment>,
+}
+
+/// Body of a `ROUTE_ADVERTISEMENT` capsule: zero or more advertised ranges.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct RouteAdvertisementCapsule {
+    pub ranges: Vec<IpAddressRange>,
+}
+
+/// Decodes a capsule body for a type the core library does not know about.
+///
+/// Returning [`Capsule::Unknown`] is almost always the right implementation
+/// of this for handlers that merely want to observe or forward the capsule;
+/// the hook mainly exists so a handler can validate the payload eagerly and
+/// reject a malformed one rather than letting it through as opaque bytes.
+pub type CapsuleBodyDecoder = fn(ty: CapsuleType, payload: &[u8]) -> Res<Capsule>;
+
+/// A set of decoders for capsule types [`Capsule`] does not know about
+/// natively, such as MASQUE proxy-control capsules.
+///
+/// This is owned per-decoder (e.g. by a [`super::capsule_reader::CapsuleReader`])
+/// rather than process-global: registering a type only changes decoding for
+/// the stream/connection that owns this registry, not every other capsule
+/// stream in the process, and the registration can be dropped simply by
+/// dropping the registry.
+#[derive(Default)]
+pub struct CapsuleRegistry {
+    handlers: HashMap<CapsuleType, CapsuleBodyDecoder>,
+}
+
+impl CapsuleRegistry {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a decoder for `ty`. Once registered, [`Self::decode_body`]
+    /// invokes `decoder` with the capsule's raw payload for that type
+    /// instead of producing a [`Capsule::Unknown`].
+    pub fn register(&mut self, ty: CapsuleType, decoder: CapsuleBodyDecoder) {
+        self.handlers.insert(ty, decoder);
+    }
+
+    /// Returns `true` if `ty` has a decode path: either built into
+    /// [`Capsule`] or registered on this registry.
+    #[must_use]
+    pub fn is_known_type(&self, ty: CapsuleType) -> bool {
+        Capsule::is_known_type(ty) || self.handlers.contains_key(&ty)
+    }
+
+    /// Like [`Capsule::decode_body`], but consults this registry's handlers
+    /// for a type the core decoder doesn't know, producing
+    /// [`Capsule::Unknown`] only if neither knows it.
+    pub(crate) fn decode_body(&self, ty: CapsuleType, payload: &[u8]) -> Res<Capsule> {
+        if Capsule::is_known_type(ty) {
+            return Capsule::decode_body(ty, payload);
+        }
+        if let Some(handler) = self.handlers.get(&ty) {
+            handler(ty, payload)
+        } else {
+            Ok(Capsule::Unknown {
+                ty,
+                payload: Bytes::from(payload.to_vec()),
+            })
+        }
+    }
+}
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Capsule {
     Datagram { payload: Bytes },
+    AddressAssign(AddressAssignCapsule),
+    AddressRequest(AddressRequestCapsule),
+    RouteAdvertisement(RouteAdvertisementCapsule),
+    /// A capsule of a type this module does not know about natively. The
+    /// payload is preserved verbatim so a proxy can still forward it, or a
+    /// caller holding a [`CapsuleRegistry`] with a handler for `ty` can
+    /// decode it via [`CapsuleRegistry::decode_body`].
+    Unknown { ty: CapsuleType, payload: Bytes },
 }
 
 impl Capsule {
-    #[cfg(test)]
     #[must_use]
-    pub const fn capsule_type(&self) -> u64 {
+    pub const fn capsule_type(&self) -> CapsuleType {
         match self {
             Self::Datagram { .. } => CAPSULE_TYPE_DATAGRAM,
+            Self::AddressAssign(_) => CAPSULE_TYPE_ADDRESS_ASSIGN,
+            Self::AddressRequest(_) => CAPSULE_TYPE_ADDRESS_REQUEST,
+            Self::RouteAdvertisement(_) => CAPSULE_TYPE_ROUTE_ADVERTISEMENT,
+            Self::Unknown { ty, .. } => *ty,
         }
     }
 
+    /// Returns `true` if `ty` is one of the types built into this module.
+    /// A handler may additionally know types this doesn't; see
+    /// [`CapsuleRegistry::is_known_type`].
+    #[must_use]
+    pub fn is_known_type(ty: CapsuleType) -> bool {
+        matches!(
+            ty,
+            CAPSULE_TYPE_DATAGRAM
+                | CAPSULE_TYPE_ADDRESS_ASSIGN
+                | CAPSULE_TYPE_ADDRESS_REQUEST
+                | CAPSULE_TYPE_ROUTE_ADVERTISEMENT
+        )
+    }
+
     #[cfg(test)]
     pub fn encode(&self, enc: &mut Encoder) {
-        enc.encode_varint(self.capsule_type());
+        enc.encode_varint(self.capsule_type().0);
         match self {
-            Self::Datagram { payload } => {
+            Self::Datagram { payload } | Self::Unknown { payload, .. } => {
                 enc.encode_vvec(payload.as_ref());
             }
+            Self::AddressAssign(AddressAssignCapsule { assigned }) => {
+                let mut body = Encoder::default();
+                for a in assigned {
+                    a.encode(&mut body);
+                }
+                enc.encode_vvec(body.as_ref());
+            }
+            Self::AddressRequest(AddressRequestCapsule { requested }) => {
+                let mut body = Encoder::default();
+                for r in requested {
+                    r.encode(&mut body);
+                }
+                enc.encode_vvec(body.as_ref());
+            }
+            Self::RouteAdvertisement(RouteAdvertisementCapsule { ranges }) => {
+                let mut body = Encoder::default();
+                for r in ranges {
+                    r.encode(&mut body);
+                }
+                enc.encode_vvec(body.as_ref());
+            }
         }
     }
 
@@ -52,25 +304,161 @@ impl Capsule {
             return Ok(None);
         }
 
-        if capsule_type == CAPSULE_TYPE_DATAGRAM {
-            let payload = decoder
-                .decode(capsule_length_usize)
-                .ok_or(crate::Error::HttpFrame)?
-                .to_vec();
-            Ok(Some(Self::Datagram {
-                payload: Bytes::from(payload),
-            }))
-        } else {
-            decoder.skip(capsule_length_usize);
-            Ok(None)
+        let ty = CapsuleType(capsule_type);
+        let payload = decoder
+            .decode(capsule_length_usize)
+            .ok_or(crate::Error::HttpFrame)?;
+        Self::decode_body(ty, payload).map(Some)
+    }
+
+    /// Like [`Self::decode`], but the `Datagram` payload is a
+    /// reference-counted slice of `buf` rather than a copy of it. Use this
+    /// when `buf` is already held as a shared [`Bytes`] (e.g. a received
+    /// WebTransport/HTTP datagram) so the payload can flow through without
+    /// an intermediate `Vec` allocation.
+    pub fn decode_borrowed(buf: &Bytes) -> Res<Option<Self>> {
+        let mut decoder = Decoder::from(buf.as_ref());
+
+        let Some(capsule_type) = decoder.decode_varint() else {
+            return Ok(None);
+        };
+
+        let Some(capsule_length) = decoder.decode_varint() else {
+            return Ok(None);
+        };
+
+        let capsule_length_usize =
+            usize::try_from(capsule_length).map_err(|_| crate::Error::HttpFrame)?;
+
+        if decoder.remaining() < capsule_length_usize {
+            return Ok(None);
+        }
+
+        let ty = CapsuleType(capsule_type);
+        let payload_start = buf.as_ref().len() - decoder.remaining();
+
+        if ty == CAPSULE_TYPE_DATAGRAM {
+            let payload = buf.slice(payload_start..payload_start + capsule_length_usize);
+            return Ok(Some(Self::Datagram { payload }));
+        }
+
+        let payload = decoder
+            .decode(capsule_length_usize)
+            .ok_or(crate::Error::HttpFrame)?;
+        Self::decode_body(ty, payload).map(Some)
+    }
+
+    pub(crate) fn decode_body(ty: CapsuleType, payload: &[u8]) -> Res<Self> {
+        match ty {
+            CAPSULE_TYPE_DATAGRAM => Ok(Self::Datagram {
+                payload: Bytes::from(payload.to_vec()),
+            }),
+            CAPSULE_TYPE_ADDRESS_ASSIGN => {
+                let mut decoder = Decoder::from(payload);
+                let mut assigned = Vec::new();
+                while decoder.remaining() > 0 {
+                    assigned.push(IpAssignment::decode(&mut decoder)?);
+                }
+                Ok(Self::AddressAssign(AddressAssignCapsule { assigned }))
+            }
+            CAPSULE_TYPE_ADDRESS_REQUEST => {
+                let mut decoder = Decoder::from(payload);
+                let mut requested = Vec::new();
+                while decoder.remaining() > 0 {
+                    requested.push(IpAssignment::decode(&mut decoder)?);
+                }
+                Ok(Self::AddressRequest(AddressRequestCapsule { requested }))
+            }
+            CAPSULE_TYPE_ROUTE_ADVERTISEMENT => {
+                let mut decoder = Decoder::from(payload);
+                let mut ranges = Vec::new();
+                while decoder.remaining() > 0 {
+                    ranges.push(IpAddressRange::decode(&mut decoder)?);
+                }
+                Ok(Self::RouteAdvertisement(RouteAdvertisementCapsule {
+                    ranges,
+                }))
+            }
+            ty => Ok(Self::Unknown {
+                ty,
+                payload: Bytes::from(payload.to_vec()),
+            }),
         }
     }
 }
 
+/// Streams a capsule's type and length prefix, then its payload chunk by
+/// chunk, into an [`Encoder`], so the caller need not assemble the payload
+/// in a buffer of its own first. The [`Encoder`] is borrowed until
+/// [`Self::finish`] and still accumulates the whole capsule; it cannot be
+/// drained between chunks. Unlike [`Capsule::encode`], which is test-only,
+/// this is the production path for encoding a large or chunked payload
+/// (e.g. a proxied datagram body).
+pub struct CapsuleWriter<'a> {
+    enc: &'a mut Encoder,
+    remaining: u64,
+}
+
+impl<'a> CapsuleWriter<'a> {
+    /// Writes the type and length-prefix varints for a capsule whose
+    /// payload will be `total_len` bytes, supplied across one or more
+    /// calls to [`Self::write_chunk`].
+    pub fn begin(enc: &'a mut Encoder, ty: CapsuleType, total_len: u64) -> Self {
+        enc.encode_varint(ty.0);
+        enc.encode_varint(total_len);
+        Self {
+            enc,
+            remaining: total_len,
+        }
+    }
+
+    /// Appends the next `chunk` of the payload.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk` would write more bytes than the `total_len`
+    /// declared to [`Self::begin`].
+    pub fn write_chunk(&mut self, chunk: &[u8]) {
+        let len = chunk.len() as u64;
+        assert!(
+            len <= self.remaining,
+            "CapsuleWriter: wrote {len} bytes but only {} remained of the declared length",
+            self.remaining
+        );
+        self.enc.encode(chunk);
+        self.remaining -= len;
+    }
+
+    /// Finishes the capsule.
+    ///
+    /// # Panics
+    ///
+    /// Panics if fewer bytes were written via [`Self::write_chunk`] than
+    /// the `total_len` declared to [`Self::begin`].
+    pub fn finish(self) {
+        assert_eq!(
+            self.remaining, 0,
+            "CapsuleWriter: finished with {} bytes of the declared length unwritten",
+            self.remaining
+        );
+    }
+}
+
+/// Test-only [`CapsuleBodyDecoder`] fixture that decodes a registered type
+/// as [`Capsule::Unknown`], shared by the registry tests in this module and
+/// in [`super::capsule_reader`].
+#[cfg(test)]
+pub(crate) fn decode_ping(ty: CapsuleType, payload: &[u8]) -> Res<Capsule> {
+    Ok(Capsule::Unknown {
+        ty,
+        payload: Bytes::from(payload.to_vec()),
+    })
+}
+
 #[cfg(test)]
 #[cfg_attr(coverage_nightly, coverage(off))]
 mod tests {
-    use neqo_common::Encoder;
+    use std::net::Ipv4Addr;
 
     use super::*;
 
@@ -140,13 +528,20 @@ mod tests {
     }
 
     #[test]
-    fn decode_unknown_capsule_type() {
+    fn decode_unregistered_unknown_capsule_type_is_preserved() {
         let data = [0x17, 0x04, 0xaa, 0xbb, 0xcc, 0xdd];
         let mut decoder = Decoder::from(&data[..]);
         let capsule = Capsule::decode(&mut decoder).unwrap();
 
-        assert_eq!(capsule, None);
+        assert_eq!(
+            capsule,
+            Some(Capsule::Unknown {
+                ty: CapsuleType(0x17),
+                payload: Bytes::from(vec![0xaa, 0xbb, 0xcc, 0xdd]),
+            })
+        );
         assert_eq!(decoder.remaining(), 0);
+        assert!(!Capsule::is_known_type(CapsuleType(0x17)));
     }
 
     #[test]
@@ -226,4 +621,208 @@ mod tests {
 
         assert_eq!(result, None);
     }
+
+    #[test]
+    fn address_assign_roundtrip() {
+        let original = Capsule::AddressAssign(AddressAssignCapsule {
+            assigned: vec![IpAssignment {
+                request_id: 1,
+                ip_addr: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+                prefix_len: 32,
+            }],
+        });
+
+        let mut enc = Encoder::default();
+        original.encode(&mut enc);
+
+        let mut dec = Decoder::from(enc.as_ref());
+        let result = Capsule::decode(&mut dec).unwrap().unwrap();
+
+        assert_eq!(original, result);
+        assert_eq!(dec.remaining(), 0);
+    }
+
+    #[test]
+    fn address_request_roundtrip_ipv6() {
+        let original = Capsule::AddressRequest(AddressRequestCapsule {
+            requested: vec![IpAssignment {
+                request_id: 7,
+                ip_addr: "2001:db8::1".parse().unwrap(),
+                prefix_len: 128,
+            }],
+        });
+
+        let mut enc = Encoder::default();
+        original.encode(&mut enc);
+
+        let mut dec = Decoder::from(enc.as_ref());
+        let result = Capsule::decode(&mut dec).unwrap().unwrap();
+
+        assert_eq!(original, result);
+        assert_eq!(dec.remaining(), 0);
+    }
+
+    #[test]
+    fn route_advertisement_roundtrip() {
+        let original = Capsule::RouteAdvertisement(RouteAdvertisementCapsule {
+            ranges: vec![IpAddressRange {
+                start: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)),
+                end: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 255)),
+                ip_proto: 0,
+            }],
+        });
+
+        let mut enc = Encoder::default();
+        original.encode(&mut enc);
+
+        let mut dec = Decoder::from(enc.as_ref());
+        let result = Capsule::decode(&mut dec).unwrap().unwrap();
+
+        assert_eq!(original, result);
+        assert_eq!(dec.remaining(), 0);
+    }
+
+    /// Per RFC 9484 section 4.7, the range body is a single `IP Version`
+    /// byte followed by Start IP, End IP, IP Protocol -- not a version byte
+    /// before each address.
+    #[test]
+    fn route_advertisement_matches_rfc9484_wire_format() {
+        let range_body = [
+            4, // IP Version (shared by both addresses)
+            10, 0, 0, 0, // Start IP Address
+            10, 0, 0, 255, // End IP Address
+            0, // IP Protocol
+        ];
+        let mut data = vec![0x03, range_body.len() as u8]; // ROUTE_ADVERTISEMENT, length
+        data.extend_from_slice(&range_body);
+
+        let mut dec = Decoder::from(&data[..]);
+        let result = Capsule::decode(&mut dec).unwrap().unwrap();
+
+        assert_eq!(
+            result,
+            Capsule::RouteAdvertisement(RouteAdvertisementCapsule {
+                ranges: vec![IpAddressRange {
+                    start: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)),
+                    end: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 255)),
+                    ip_proto: 0,
+                }],
+            })
+        );
+        assert_eq!(dec.remaining(), 0);
+    }
+
+    #[test]
+    fn registered_capsule_type_is_decoded_by_handler() {
+        let ty = CapsuleType(0x20);
+        let mut registry = CapsuleRegistry::new();
+        registry.register(ty, decode_ping);
+        assert!(registry.is_known_type(ty));
+        assert!(!Capsule::is_known_type(ty));
+
+        let capsule = registry.decode_body(ty, &[0x01, 0x02]).unwrap();
+
+        assert_eq!(
+            capsule,
+            Capsule::Unknown {
+                ty,
+                payload: Bytes::from(vec![0x01, 0x02]),
+            }
+        );
+    }
+
+    #[test]
+    fn unregistered_registry_falls_back_to_unknown() {
+        let registry = CapsuleRegistry::new();
+        let ty = CapsuleType(0x21);
+
+        assert!(!registry.is_known_type(ty));
+        let capsule = registry.decode_body(ty, &[0xaa]).unwrap();
+
+        assert_eq!(
+            capsule,
+            Capsule::Unknown {
+                ty,
+                payload: Bytes::from(vec![0xaa]),
+            }
+        );
+    }
+
+    #[test]
+    fn decode_borrowed_datagram_shares_buffer_without_copy() {
+        let payload = vec![0x01, 0x02, 0x03, 0x04, 0x05];
+        let capsule = Capsule::Datagram {
+            payload: Bytes::from(payload.clone()),
+        };
+        let mut enc = Encoder::default();
+        capsule.encode(&mut enc);
+        let buf = Bytes::from(enc.as_ref().to_vec());
+
+        let result = Capsule::decode_borrowed(&buf).unwrap();
+
+        assert_eq!(
+            result,
+            Some(Capsule::Datagram {
+                payload: Bytes::from(payload)
+            })
+        );
+        // Type and length are one byte each, so the payload starts at 2.
+        let Some(Capsule::Datagram { payload: shared }) = &result else {
+            panic!("expected a Datagram capsule");
+        };
+        assert_eq!(shared.as_ref().as_ptr(), buf.as_ref()[2..].as_ptr());
+    }
+
+    #[test]
+    fn decode_borrowed_non_datagram_still_works() {
+        let original = Capsule::AddressRequest(AddressRequestCapsule {
+            requested: vec![IpAssignment {
+                request_id: 3,
+                ip_addr: IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7)),
+                prefix_len: 24,
+            }],
+        });
+        let mut enc = Encoder::default();
+        original.encode(&mut enc);
+        let buf = Bytes::from(enc.as_ref().to_vec());
+
+        let result = Capsule::decode_borrowed(&buf).unwrap();
+
+        assert_eq!(result, Some(original));
+    }
+
+    #[test]
+    fn capsule_writer_streams_chunks_and_matches_encode() {
+        let payload = vec![0x01, 0x02, 0x03, 0x04, 0x05];
+        let expected = Capsule::Datagram {
+            payload: Bytes::from(payload.clone()),
+        };
+        let mut expected_enc = Encoder::default();
+        expected.encode(&mut expected_enc);
+
+        let mut enc = Encoder::default();
+        let mut writer = CapsuleWriter::begin(&mut enc, CAPSULE_TYPE_DATAGRAM, payload.len() as u64);
+        for chunk in payload.chunks(2) {
+            writer.write_chunk(chunk);
+        }
+        writer.finish();
+
+        assert_eq!(enc.as_ref(), expected_enc.as_ref());
+    }
+
+    #[test]
+    #[should_panic(expected = "only 1 remained")]
+    fn capsule_writer_panics_on_overwrite() {
+        let mut enc = Encoder::default();
+        let mut writer = CapsuleWriter::begin(&mut enc, CAPSULE_TYPE_DATAGRAM, 1);
+        writer.write_chunk(&[0x01, 0x02]);
+    }
+
+    #[test]
+    #[should_panic(expected = "1 bytes of the declared length unwritten")]
+    fn capsule_writer_panics_on_early_finish() {
+        let mut enc = Encoder::default();
+        let writer = CapsuleWriter::begin(&mut enc, CAPSULE_TYPE_DATAGRAM, 1);
+        writer.finish();
+    }
 }
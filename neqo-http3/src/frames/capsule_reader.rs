@@ -0,0 +1,325 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::collections::VecDeque;
+
+use neqo_common::Decoder;
+
+#[cfg(test)]
+use super::capsule::decode_ping;
+use super::capsule::{Capsule, CapsuleBodyDecoder, CapsuleRegistry, CapsuleType};
+use crate::{Error, Res};
+
+enum State {
+    NeedType,
+    NeedLength { ty: CapsuleType },
+    NeedPayload { ty: CapsuleType, remaining: u64 },
+    /// An unrecognized capsule type: discard its payload incrementally
+    /// rather than buffering it, since we have nothing to do with the
+    /// bytes and the declared length is attacker-controlled. Note this
+    /// means an unregistered capsule never reaches the caller at all, even
+    /// as [`Capsule::Unknown`] -- unlike [`Capsule::decode`], which
+    /// preserves the bytes for forwarding. A caller that needs to forward
+    /// capsules it doesn't decode should register a pass-through handler
+    /// for the types it cares about via
+    /// [`CapsuleReader::register_capsule_type`].
+    SkipPayload { remaining: u64 },
+}
+
+/// Reassembles [`Capsule`]s from a byte stream that may arrive in
+/// arbitrarily small chunks, such as an HTTP stream carrying the Capsule
+/// Protocol. Unlike [`Capsule::decode`], which needs the whole capsule in
+/// one contiguous buffer, `CapsuleReader` is a state machine that buffers
+/// only the bytes needed for whichever part of the current capsule (type,
+/// length, or payload) hasn't arrived yet, so progress made on a partial
+/// capsule is never thrown away.
+pub struct CapsuleReader {
+    state: State,
+    varint_buf: Vec<u8>,
+    payload_buf: Vec<u8>,
+    max_capsule_len: u64,
+    ready: VecDeque<Capsule>,
+    /// Decoders for capsule types this reader's stream understands beyond
+    /// the built-in set, scoped to this reader alone: registering a type on
+    /// one stream's reader has no effect on any other stream's.
+    registry: CapsuleRegistry,
+}
+
+impl CapsuleReader {
+    /// Creates a reader that rejects any capsule whose declared length
+    /// exceeds `max_capsule_len` instead of buffering it.
+    #[must_use]
+    pub fn new(max_capsule_len: u64) -> Self {
+        Self {
+            state: State::NeedType,
+            varint_buf: Vec::new(),
+            payload_buf: Vec::new(),
+            max_capsule_len,
+            ready: VecDeque::new(),
+            registry: CapsuleRegistry::new(),
+        }
+    }
+
+    /// Registers a decoder for a capsule type this reader does not
+    /// understand natively, such as a MASQUE proxy-control capsule. Only
+    /// affects this reader, not other streams or connections.
+    pub fn register_capsule_type(&mut self, ty: CapsuleType, decoder: CapsuleBodyDecoder) {
+        self.registry.register(ty, decoder);
+    }
+
+    /// Feeds newly received bytes into the reader. Completed capsules
+    /// become available through [`Iterator::next`].
+    pub fn push(&mut self, mut data: &[u8]) -> Res<()> {
+        while !data.is_empty() {
+            match self.state {
+                State::NeedType => {
+                    let Some((value, consumed)) = Self::take_varint(&mut self.varint_buf, data)
+                    else {
+                        return Ok(());
+                    };
+                    data = &data[consumed..];
+                    self.varint_buf.clear();
+                    self.state = State::NeedLength {
+                        ty: CapsuleType(value),
+                    };
+                }
+                State::NeedLength { ty } => {
+                    let Some((value, consumed)) = Self::take_varint(&mut self.varint_buf, data)
+                    else {
+                        return Ok(());
+                    };
+                    data = &data[consumed..];
+                    self.varint_buf.clear();
+                    if value > self.max_capsule_len {
+                        return Err(Error::HttpFrame);
+                    }
+                    self.state = if self.registry.is_known_type(ty) {
+                        self.payload_buf.clear();
+                        State::NeedPayload {
+                            ty,
+                            remaining: value,
+                        }
+                    } else {
+                        State::SkipPayload { remaining: value }
+                    };
+                }
+                State::NeedPayload { ty, remaining } => {
+                    let take = usize::try_from(remaining)
+                        .map_err(|_| Error::HttpFrame)?
+                        .min(data.len());
+                    self.payload_buf.extend_from_slice(&data[..take]);
+                    data = &data[take..];
+                    let remaining = remaining - take as u64;
+                    self.state = if remaining == 0 {
+                        let capsule = self.registry.decode_body(ty, &self.payload_buf)?;
+                        self.payload_buf.clear();
+                        self.ready.push_back(capsule);
+                        State::NeedType
+                    } else {
+                        State::NeedPayload { ty, remaining }
+                    };
+                }
+                State::SkipPayload { remaining } => {
+                    let take = usize::try_from(remaining)
+                        .map_err(|_| Error::HttpFrame)?
+                        .min(data.len());
+                    data = &data[take..];
+                    let remaining = remaining - take as u64;
+                    self.state = if remaining == 0 {
+                        State::NeedType
+                    } else {
+                        State::SkipPayload { remaining }
+                    };
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Buffers bytes from `data` into `buf` until a complete QUIC varint is
+    /// present, per the self-describing length encoded in its first byte's
+    /// top two bits. Returns the decoded value and how many bytes of `data`
+    /// it took to complete it, or `None` if `data` ran out first (in which
+    /// case all of `data` has been appended to `buf`).
+    fn take_varint(buf: &mut Vec<u8>, data: &[u8]) -> Option<(u64, usize)> {
+        let first = *buf.first().or_else(|| data.first())?;
+        let needed = 1usize << (first >> 6);
+        let take = (needed - buf.len()).min(data.len());
+        buf.extend_from_slice(&data[..take]);
+        if buf.len() < needed {
+            return None;
+        }
+        let value = Decoder::from(buf.as_slice())
+            .decode_varint()
+            .expect("buf holds exactly one complete varint");
+        Some((value, take))
+    }
+}
+
+impl Iterator for CapsuleReader {
+    type Item = Capsule;
+
+    /// Returns the next completed capsule, if any are buffered.
+    fn next(&mut self) -> Option<Capsule> {
+        self.ready.pop_front()
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(coverage_nightly, coverage(off))]
+mod tests {
+    use neqo_common::{Bytes, Encoder};
+
+    use super::*;
+
+    #[test]
+    fn reads_capsule_fed_one_byte_at_a_time() {
+        let capsule = Capsule::Datagram {
+            payload: Bytes::from(vec![0x01, 0x02, 0x03]),
+        };
+        let mut enc = Encoder::default();
+        capsule.encode(&mut enc);
+        let data = enc.as_ref().to_vec();
+
+        let mut reader = CapsuleReader::new(1000);
+        for byte in &data {
+            reader.push(&[*byte]).unwrap();
+        }
+
+        assert_eq!(reader.next(), Some(capsule));
+        assert_eq!(reader.next(), None);
+    }
+
+    #[test]
+    fn reads_capsule_fed_in_one_push() {
+        let capsule = Capsule::Datagram {
+            payload: Bytes::from(vec![0xaa; 300]),
+        };
+        let mut enc = Encoder::default();
+        capsule.encode(&mut enc);
+        let data = enc.as_ref().to_vec();
+
+        let mut reader = CapsuleReader::new(1000);
+        reader.push(&data).unwrap();
+
+        assert_eq!(reader.next(), Some(capsule));
+        assert_eq!(reader.next(), None);
+    }
+
+    #[test]
+    fn reads_multiple_capsules_across_pushes() {
+        let first = Capsule::Datagram {
+            payload: Bytes::from(vec![0x01]),
+        };
+        let second = Capsule::Datagram {
+            payload: Bytes::from(vec![0x02, 0x03]),
+        };
+        let mut enc = Encoder::default();
+        first.encode(&mut enc);
+        second.encode(&mut enc);
+        let data = enc.as_ref().to_vec();
+
+        let mut reader = CapsuleReader::new(1000);
+        let (first_half, second_half) = data.split_at(data.len() / 2);
+        reader.push(first_half).unwrap();
+        reader.push(second_half).unwrap();
+
+        assert_eq!(reader.next(), Some(first));
+        assert_eq!(reader.next(), Some(second));
+        assert_eq!(reader.next(), None);
+    }
+
+    #[test]
+    fn skips_unknown_capsule_without_emitting_it() {
+        let known = Capsule::Datagram {
+            payload: Bytes::from(vec![0x09]),
+        };
+        let mut enc = Encoder::default();
+        enc.encode_varint(0x17u64); // unknown capsule type
+        enc.encode_vvec(&[0xaa, 0xbb, 0xcc]);
+        known.encode(&mut enc);
+        let data = enc.as_ref().to_vec();
+
+        let mut reader = CapsuleReader::new(1000);
+        reader.push(&data).unwrap();
+
+        assert_eq!(reader.next(), Some(known));
+        assert_eq!(reader.next(), None);
+    }
+
+    #[test]
+    fn registered_capsule_type_is_decoded_instead_of_skipped() {
+        let ty = CapsuleType(0x20);
+        let mut enc = Encoder::default();
+        enc.encode_varint(ty.0);
+        enc.encode_vvec(&[0x01, 0x02]);
+        let data = enc.as_ref().to_vec();
+
+        let mut reader = CapsuleReader::new(1000);
+        reader.register_capsule_type(ty, decode_ping);
+        reader.push(&data).unwrap();
+
+        assert_eq!(
+            reader.next(),
+            Some(Capsule::Unknown {
+                ty,
+                payload: Bytes::from(vec![0x01, 0x02]),
+            })
+        );
+        assert_eq!(reader.next(), None);
+    }
+
+    #[test]
+    fn registration_is_scoped_to_one_reader() {
+        let ty = CapsuleType(0x20);
+        let mut enc = Encoder::default();
+        enc.encode_varint(ty.0);
+        enc.encode_vvec(&[0x01, 0x02]);
+        let data = enc.as_ref().to_vec();
+
+        let mut registered = CapsuleReader::new(1000);
+        registered.register_capsule_type(ty, decode_ping);
+        registered.push(&data).unwrap();
+        assert!(registered.next().is_some());
+
+        let mut unregistered = CapsuleReader::new(1000);
+        unregistered.push(&data).unwrap();
+        assert_eq!(unregistered.next(), None);
+    }
+
+    #[test]
+    fn rejects_capsule_longer_than_max_len() {
+        let mut enc = Encoder::default();
+        enc.encode_varint(0u64); // DATAGRAM
+        enc.encode_varint(100u64); // declared length
+        let data = enc.as_ref().to_vec();
+
+        let mut reader = CapsuleReader::new(10);
+        let result = reader.push(&data);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn skips_large_unknown_capsule_fed_in_pieces() {
+        let known = Capsule::Datagram {
+            payload: Bytes::from(vec![0x42]),
+        };
+        let mut enc = Encoder::default();
+        enc.encode_varint(0x17u64); // unknown capsule type
+        enc.encode_vvec(&[0u8; 5_000]);
+        known.encode(&mut enc);
+        let data = enc.as_ref().to_vec();
+
+        let mut reader = CapsuleReader::new(10_000);
+        for chunk in data.chunks(37) {
+            reader.push(chunk).unwrap();
+        }
+
+        assert_eq!(reader.next(), Some(known));
+        assert_eq!(reader.next(), None);
+    }
+}
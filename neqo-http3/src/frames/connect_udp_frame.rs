@@ -18,15 +18,11 @@ pub enum Frame {
 
 impl FrameDecoder<Self> for Frame {
     fn decode(frame_type: HFrameType, _frame_len: u64, data: Option<&[u8]>) -> Res<Option<Self>> {
-        if frame_type == CAPSULE_TYPE_DATAGRAM {
+        if Self::is_known_type(frame_type) {
             if let Some(payload) = data {
                 let mut decoder = Decoder::from(payload);
-                if let Some(capsule) = Capsule::decode(&mut decoder)? {
-                    match capsule {
-                        Capsule::Datagram { payload } => {
-                            return Ok(Some(Self::Datagram { payload }));
-                        }
-                    }
+                if let Some(Capsule::Datagram { payload }) = Capsule::decode(&mut decoder)? {
+                    return Ok(Some(Self::Datagram { payload }));
                 }
             }
         }
@@ -34,10 +30,28 @@ impl FrameDecoder<Self> for Frame {
     }
 
     fn is_known_type(frame_type: HFrameType) -> bool {
+        // CONNECT-UDP (RFC 9298) only carries DATAGRAM capsules. The wider
+        // set in `Capsule::is_known_type` also covers CONNECT-IP capsule
+        // types (ADDRESS_ASSIGN, ...) that `Frame` has no variant for, so
+        // `decode` above drops them; this must stay narrower than that.
         frame_type == CAPSULE_TYPE_DATAGRAM
     }
 }
 
+impl Frame {
+    /// Like [`FrameDecoder::decode`], but shares `buf` with the returned
+    /// `Datagram` payload via [`Capsule::decode_borrowed`] instead of
+    /// copying it. Use this when `buf` is already a `Bytes`, as it is for
+    /// a received WebTransport/HTTP datagram, to avoid the extra
+    /// allocation `FrameDecoder::decode` pays for.
+    pub fn decode_borrowed(buf: &Bytes) -> Res<Option<Self>> {
+        if let Some(Capsule::Datagram { payload }) = Capsule::decode_borrowed(buf)? {
+            return Ok(Some(Self::Datagram { payload }));
+        }
+        Ok(None)
+    }
+}
+
 #[cfg(test)]
 #[cfg_attr(coverage_nightly, coverage(off))]
 mod tests {
@@ -99,6 +113,9 @@ mod tests {
         assert!(!<Frame as FrameDecoder<Frame>>::is_known_type(HFrameType(
             0x01
         )));
+        assert!(!<Frame as FrameDecoder<Frame>>::is_known_type(HFrameType(
+            0x99
+        )));
     }
 
     #[test]
@@ -118,4 +135,24 @@ mod tests {
             <Frame as FrameDecoder<Frame>>::decode(HFrameType(0x99), frame_len, Some(&data));
         assert_eq!(result.unwrap(), None);
     }
+
+    #[test]
+    fn decode_borrowed_datagram_frame() {
+        let payload = vec![0x01, 0x02, 0x03, 0x04, 0x05];
+        let capsule = Capsule::Datagram {
+            payload: Bytes::from(payload.clone()),
+        };
+        let mut enc = Encoder::default();
+        capsule.encode(&mut enc);
+        let buf = Bytes::from(enc.as_ref().to_vec());
+
+        let result = Frame::decode_borrowed(&buf).unwrap();
+
+        assert_eq!(
+            result,
+            Some(Frame::Datagram {
+                payload: Bytes::from(payload)
+            })
+        );
+    }
 }
@@ -0,0 +1,11 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+pub(crate) mod capsule;
+pub(crate) mod capsule_reader;
+pub(crate) mod connect_udp_frame;
+
+pub(crate) use capsule_reader::CapsuleReader;